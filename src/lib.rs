@@ -2,7 +2,10 @@
 //!
 //! This crate provides an implementation of the `DelayMs` and `DelayUs` traits from `embedded-hal`
 //! for various STM32 MCUs, as the corresponding HALs often only provide functionality for delays
-//! based on the system timer.
+//! based on the system timer. It also provides [`CountDownTimer`], a non-blocking `CountDown`
+//! implementation built on the same peripherals, for code that wants a pollable timeout instead of
+//! a blocking delay. `TimerDelay` additionally accepts `fugit` durations directly
+//! (`delay.delay(500.millis())`) and implements the `embedded-hal` 1.0 `DelayNs` trait.
 //!
 //! # Usage
 //!
@@ -13,82 +16,35 @@
 //!
 //! # Limitations
 //!
-//! For high accuracy, the corresponding APB clock should be a multiple of 1000000. The code may
-//! provide reduced accuracy for very long delays (i.e., >65k milliseconds or microseconds) as the
-//! time is split into multiple shorter delays. Similarly, the code may provide reduced accuracy if
-//! for `DelayMs` if the timer input clock is faster than 65536kHz, as then the limited prescaler
-//! requires the whole delay loop to be repeated twice.
+//! Delays are computed directly in native timer ticks using 64-bit arithmetic, so timing stays
+//! accurate regardless of the APB clock frequency. Very long delays (longer than the 16-bit
+//! counter can cover in one run) are simply split across multiple counter reloads. A reload with
+//! value `n` runs for `n + 1` ticks before the update event fires; the full `0xffff` reloads
+//! account for this, but the final partial reload does not, so a delay whose tick count is not a
+//! multiple of `0x10000` blocks for one tick longer than requested; this overshoot is harmless
+//! but worth knowing about for very tight timing budgets.
 #![no_std]
 
 #[cfg(not(feature = "device-selected"))]
 compile_error!("A specific device needs to be selected via the appropriate feature flag.");
 
-use embedded_hal::blocking::delay::{DelayMs, DelayUs};
-#[cfg(feature = "stm32f411")]
-use stm32f4xx_hal::{bb, rcc::Clocks, stm32::RCC, stm32::TIM1};
+pub mod mono;
+mod timer;
+pub mod uptime;
 
-pub trait TimerExt {
-    unsafe fn enable(&mut self);
-    unsafe fn disable(&mut self);
-
-    fn calc_pre(clocks: Clocks) -> (u32, u32);
-    unsafe fn delay(&mut self, prescaler: u32, time: u16);
-}
+pub use timer::{Apb, CountDownTimer, TimerExt};
+pub use uptime::UptimeTimer;
 
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use eh1::delay::DelayNs;
+use fugit::MicrosDurationU32;
 #[cfg(feature = "stm32f411")]
-impl TimerExt for TIM1 {
-    unsafe fn enable(&mut self) {
-        // Enable and reset the peripheral.
-        let rcc = &(*RCC::ptr());
-        bb::set(&rcc.apb2enr, 0);
-        bb::set(&rcc.apb2rstr, 0);
-        bb::clear(&rcc.apb2rstr, 0);
-        // Select down-counting mode.
-        self.cr1.modify(|_, w| w.dir().set_bit());
-    }
-
-    unsafe fn disable(&mut self) {
-        // Disable the peripheral.
-        let rcc = &(*RCC::ptr());
-        bb::set(&rcc.apb2rstr, 0);
-        bb::clear(&rcc.apb2rstr, 0);
-        bb::clear(&rcc.apb2enr, 0);
-    }
-
-    fn calc_pre(clocks: Clocks) -> (u32, u32) {
-        let pclk_mul = if clocks.ppre2() == 1 { 1 } else { 2 };
-        let freq_in = clocks.pclk2().0 * pclk_mul;
-        // Higher prescalers than required are OK here, because they result in delays longer than
-        // expected.
-        let us_pre = (freq_in + 999999) / 1000000;
-        let ms_pre = (freq_in + 999) / 1000;
-        (us_pre, ms_pre)
-    }
-
-    unsafe fn delay(&mut self, prescaler: u32, time: u16) {
-        // Frequencies greater than 65MHz result in prescaler values larger than 0xffff, so we need
-        // to repeat the delay loop several times here.
-        let repetitions = (prescaler >> 16) + 1;
-        let prescaler = (prescaler & 0xffff) as u16;
-        self.psc.write(|w| w.psc().bits(prescaler));
-        for _ in 0..repetitions {
-            // Clear the update flag.
-            self.sr.write(|w| w.uif().set_bit());
-            // Start the counter.
-            self.cnt.write(|w| unsafe { w.cnt().bits(time) });
-            self.cr1.modify(|_, w| w.cen().set_bit());
-            // Wait until the counter has reached zero.
-            while !self.sr.read().uif().bit_is_set() {}
-            // Pause the counter.
-            self.cr1.modify(|_, w| w.cen().clear_bit());
-        }
-    }
-}
+use stm32f4xx_hal::rcc::Clocks;
 
 pub struct TimerDelay<T> {
     t: T,
-    us_pre: u32,
-    ms_pre: u32,
+    /// Frequency (in Hz) of the clock fed into `t`'s prescaler.
+    freq_in: u32,
 }
 
 impl<T> TimerDelay<T>
@@ -99,8 +55,8 @@ where
         unsafe {
             t.enable();
         };
-        let (us_pre, ms_pre) = T::calc_pre(clocks);
-        TimerDelay { t, us_pre, ms_pre }
+        let freq_in = T::input_clock(&clocks);
+        TimerDelay { t, freq_in }
     }
 
     pub fn free(mut self) -> T {
@@ -109,6 +65,40 @@ where
         }
         self.t
     }
+
+    /// Computes the number of native timer ticks needed to cover `time` (in units of `1/divisor`
+    /// seconds) at this timer's input clock, using 64-bit arithmetic so the result stays exact for
+    /// any `freq_in`/`time` combination.
+    fn ticks(&self, time: u32, divisor: u32) -> u64 {
+        (time as u64) * (self.freq_in as u64) / (divisor as u64)
+    }
+
+    /// Runs the counter, unprescaled, for `total_ticks` native timer ticks (one tick more if
+    /// `total_ticks` is not a multiple of `0x10000`), splitting the run across as many 16-bit
+    /// counter reloads as necessary.
+    fn delay_ticks(&mut self, total_ticks: u64) {
+        let full_reloads = total_ticks / 0x1_0000;
+        let remainder = (total_ticks % 0x1_0000) as u16;
+        for _ in 0..full_reloads {
+            unsafe {
+                self.t.delay(0, 0xffff);
+            }
+        }
+        if remainder > 0 {
+            unsafe {
+                self.t.delay(0, remainder);
+            }
+        }
+    }
+
+    /// Blocks for `duration`, e.g. `delay.delay(500.millis())`.
+    pub fn delay<D>(&mut self, duration: D)
+    where
+        D: Into<MicrosDurationU32>,
+    {
+        let ticks = self.ticks(duration.into().ticks(), 1_000_000);
+        self.delay_ticks(ticks);
+    }
 }
 
 impl<T> DelayMs<u8> for TimerDelay<T>
@@ -116,9 +106,8 @@ where
     T: TimerExt,
 {
     fn delay_ms(&mut self, ms: u8) {
-        unsafe {
-            self.t.delay(self.ms_pre, ms as u16);
-        }
+        let ticks = self.ticks(ms as u32, 1_000);
+        self.delay_ticks(ticks);
     }
 }
 
@@ -127,9 +116,8 @@ where
     T: TimerExt,
 {
     fn delay_ms(&mut self, ms: u16) {
-        unsafe {
-            self.t.delay(self.ms_pre, ms as u16);
-        }
+        let ticks = self.ticks(ms as u32, 1_000);
+        self.delay_ticks(ticks);
     }
 }
 
@@ -137,16 +125,9 @@ impl<T> DelayMs<u32> for TimerDelay<T>
 where
     T: TimerExt,
 {
-    fn delay_ms(&mut self, mut ms: u32) {
-        while ms > 0xffff {
-            unsafe {
-                self.t.delay(self.ms_pre, 0xffff);
-            }
-            ms -= 0xffff;
-        }
-        unsafe {
-            self.t.delay(self.ms_pre, ms as u16);
-        }
+    fn delay_ms(&mut self, ms: u32) {
+        let ticks = self.ticks(ms, 1_000);
+        self.delay_ticks(ticks);
     }
 }
 
@@ -155,9 +136,8 @@ where
     T: TimerExt,
 {
     fn delay_us(&mut self, us: u8) {
-        unsafe {
-            self.t.delay(self.us_pre, us as u16);
-        }
+        let ticks = self.ticks(us as u32, 1_000_000);
+        self.delay_ticks(ticks);
     }
 }
 
@@ -166,9 +146,8 @@ where
     T: TimerExt,
 {
     fn delay_us(&mut self, us: u16) {
-        unsafe {
-            self.t.delay(self.us_pre, us as u16);
-        }
+        let ticks = self.ticks(us as u32, 1_000_000);
+        self.delay_ticks(ticks);
     }
 }
 
@@ -176,15 +155,31 @@ impl<T> DelayUs<u32> for TimerDelay<T>
 where
     T: TimerExt,
 {
-    fn delay_us(&mut self, mut us: u32) {
-        while us > 0xffff {
-            unsafe {
-                self.t.delay(self.us_pre, 0xffff);
-            }
-            us -= 0xffff;
-        }
-        unsafe {
-            self.t.delay(self.us_pre, us as u16);
-        }
+    fn delay_us(&mut self, us: u32) {
+        let ticks = self.ticks(us, 1_000_000);
+        self.delay_ticks(ticks);
+    }
+}
+
+impl<T> DelayNs for TimerDelay<T>
+where
+    T: TimerExt,
+{
+    fn delay_ns(&mut self, ns: u32) {
+        let ticks = (ns as u64) * (self.freq_in as u64) / 1_000_000_000;
+        // Round a non-zero request up to at least one tick rather than silently not delaying at
+        // all, since `ns` can easily be smaller than one tick at typical APB clock frequencies.
+        let ticks = if ns > 0 { ticks.max(1) } else { ticks };
+        self.delay_ticks(ticks);
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        let ticks = self.ticks(us, 1_000_000);
+        self.delay_ticks(ticks);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        let ticks = self.ticks(ms, 1_000);
+        self.delay_ticks(ticks);
     }
 }
@@ -0,0 +1,137 @@
+//! RTIC `Monotonic` implementation backed by a [`TimerExt`] peripheral.
+//!
+//! The timer free-runs in up-counting mode at a fixed `TIMER_HZ` tick rate (derived from
+//! [`TimerExt::input_clock`]), and [`MonoTimer::set_compare`] programs capture/compare channel 1
+//! with the target tick so the compare interrupt wakes the RTIC scheduler. A software overflow
+//! counter, advanced on the timer's own update-event interrupt, extends the hardware's 16-bit
+//! counter to a full `u32` of ticks, matching the `fugit`-based `Instant`/`Duration` this module
+//! exposes. This lets an application that already uses this crate for delays reuse the same TIM
+//! peripheral as its RTIC time source instead of reserving `SysTick`.
+//!
+//! Capture/compare channel 1 only ever matches the low 16 bits of a target instant, so a target
+//! more than one overflow (`0x10000` ticks) in the future would otherwise alias against every
+//! intervening wraparound. To avoid firing early, the compare interrupt is kept disabled until
+//! [`on_interrupt`](Monotonic::on_interrupt) observes the overflow counter reach (or, if interrupts
+//! were masked long enough to miss it, pass) the target's epoch (`target >> 16`); only then is it
+//! safe for the hardware match to mean "due now". Any stale `CC1IF` match picked up while the
+//! interrupt was disabled is cleared before it is re-enabled, so only a fresh match can fire it.
+
+use fugit::{Duration, Instant};
+use rtic_monotonic::Monotonic;
+
+use crate::timer::TimerExt;
+#[cfg(feature = "stm32f411")]
+use stm32f4xx_hal::rcc::Clocks;
+
+/// RTIC monotonic timer ticking at `TIMER_HZ` Hz.
+pub struct MonoTimer<T, const TIMER_HZ: u32> {
+    t: T,
+    overflow: u32,
+    /// Full-resolution tick target last passed to `set_compare`.
+    compare: u32,
+}
+
+impl<T, const TIMER_HZ: u32> MonoTimer<T, TIMER_HZ>
+where
+    T: TimerExt,
+{
+    #[cfg(feature = "stm32f411")]
+    pub fn init(mut t: T, clocks: &Clocks) -> MonoTimer<T, TIMER_HZ> {
+        unsafe {
+            t.enable();
+        }
+        let freq_in = T::input_clock(clocks);
+        let divisor = (freq_in / TIMER_HZ).max(1);
+        let prescaler = (divisor - 1).min(0xffff) as u16;
+        unsafe {
+            t.start_free_running(prescaler);
+            t.enable_update_interrupt();
+        }
+        MonoTimer {
+            t,
+            overflow: 0,
+            compare: 0,
+        }
+    }
+
+    fn ticks(&self) -> u32 {
+        (self.overflow << 16) | (self.t.counter() as u32)
+    }
+
+    /// The epoch (overflow count) the current `compare` target falls in.
+    fn compare_epoch(&self) -> u32 {
+        self.compare >> 16
+    }
+}
+
+impl<T, const TIMER_HZ: u32> Monotonic for MonoTimer<T, TIMER_HZ>
+where
+    T: TimerExt,
+{
+    type Instant = Instant<u32, 1, TIMER_HZ>;
+    type Duration = Duration<u32, 1, TIMER_HZ>;
+
+    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+
+    fn now(&mut self) -> Self::Instant {
+        Self::Instant::from_ticks(self.ticks())
+    }
+
+    fn zero() -> Self::Instant {
+        Self::Instant::from_ticks(0)
+    }
+
+    unsafe fn reset(&mut self) {
+        self.t.clear_compare_flag();
+    }
+
+    fn set_compare(&mut self, instant: Self::Instant) {
+        let target = instant.duration_since_epoch().ticks();
+        self.compare = target;
+        unsafe {
+            self.t.set_compare(target as u16);
+            if self.compare_epoch() <= self.overflow {
+                // The target's epoch is the current one, or it has already fully elapsed (e.g.
+                // interrupts were masked across an entire epoch). `overflow` only ever increases,
+                // so an already-passed epoch can never again compare equal to it; using `<=`
+                // here (rather than `==`) is what lets this case still enable the interrupt
+                // instead of hanging forever. Clear any stale match left over from the previous
+                // target's sweep across this epoch before trusting a fresh one.
+                self.t.clear_compare_flag();
+                self.t.enable_compare_interrupt();
+            } else {
+                // The target is more than one wraparound away: leave the compare interrupt
+                // disabled so the aliased low-16-bit match along the way doesn't fire early.
+                // `on_interrupt` re-enables it once the overflow count reaches this epoch.
+                self.t.disable_compare_interrupt();
+                // CNT sweeps past this CCR value once per intervening epoch, which sets CC1IF
+                // regardless of CC1IE. Clear it so that stale match isn't mistaken for the real
+                // one the moment `on_interrupt` re-enables the interrupt.
+                self.t.clear_compare_flag();
+            }
+        }
+    }
+
+    fn clear_compare_flag(&mut self) {
+        unsafe {
+            self.t.clear_compare_flag();
+        }
+    }
+
+    fn on_interrupt(&mut self) {
+        if self.t.is_update_pending() {
+            unsafe {
+                self.t.clear_update_flag();
+            }
+            self.overflow = self.overflow.wrapping_add(1);
+            if self.compare_epoch() <= self.overflow {
+                unsafe {
+                    // See `set_compare`: clear any stale match picked up while the interrupt was
+                    // disabled so only a fresh one can fire it.
+                    self.t.clear_compare_flag();
+                    self.t.enable_compare_interrupt();
+                }
+            }
+        }
+    }
+}
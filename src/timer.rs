@@ -0,0 +1,408 @@
+//! Generic timer backend used to implement [`TimerDelay`](crate::TimerDelay).
+//!
+//! [`TimerExt`] is implemented for every general-purpose *16-bit* timer peripheral the enabled
+//! device feature provides, regardless of which APB bus the timer is wired to. Two macros expand
+//! one impl per `(peripheral, bus, reset register, enable register, bit)` tuple: [`timers!`] for
+//! timers with a `CR1.DIR` bit (down-counting capable), and [`up_timers!`] for up-counting-only
+//! timers that lack it. Adding support for another 16-bit timer is then a matter of adding a tuple
+//! to the right macro invocation rather than duplicating the bit-banding and prescaler logic.
+//!
+//! TIM2 and TIM5 are 32-bit counters on STM32F4 and so cannot implement this 16-bit-oriented
+//! trait as-is; they, and other device families (F1/F3/L0), are left for a future change once
+//! there is a way to verify the generated code against their actual PAC types.
+
+#[cfg(feature = "stm32f411")]
+use stm32f4xx_hal::{
+    bb,
+    rcc::Clocks,
+    stm32::{RCC, TIM1, TIM10, TIM11, TIM3, TIM4, TIM9},
+};
+
+/// The APB bus a timer peripheral is clocked from.
+///
+/// STM32 APB prescalers double the clock fed to the timers connected to that bus whenever the bus
+/// itself is divided down (`ppre != 1`), so the input clock of a timer is not simply `pclk`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Apb {
+    Apb1,
+    Apb2,
+}
+
+impl Apb {
+    #[cfg(feature = "stm32f411")]
+    fn ppre(self, clocks: &Clocks) -> u8 {
+        match self {
+            Apb::Apb1 => clocks.ppre1(),
+            Apb::Apb2 => clocks.ppre2(),
+        }
+    }
+
+    #[cfg(feature = "stm32f411")]
+    fn pclk_hz(self, clocks: &Clocks) -> u32 {
+        match self {
+            Apb::Apb1 => clocks.pclk1().0,
+            Apb::Apb2 => clocks.pclk2().0,
+        }
+    }
+}
+
+/// Trait implemented for all timer peripherals that can back a [`TimerDelay`](crate::TimerDelay)
+/// or a [`CountDownTimer`].
+pub trait TimerExt {
+    /// The APB bus this timer peripheral is clocked from.
+    const APB: Apb;
+
+    unsafe fn enable(&mut self);
+    unsafe fn disable(&mut self);
+
+    /// Programs the prescaler and reload value. Does not touch the counter-enable bit.
+    unsafe fn load(&mut self, prescaler: u16, reload: u16);
+    /// Starts the (already loaded) counter.
+    unsafe fn start(&mut self);
+    /// Stops the counter.
+    unsafe fn stop(&mut self);
+    /// Returns whether the update event flag is set.
+    fn is_update_pending(&self) -> bool;
+    /// Clears the update event flag.
+    unsafe fn clear_update_flag(&mut self);
+    /// Enables the update-event interrupt (`DIER.UIE`).
+    unsafe fn enable_update_interrupt(&mut self);
+
+    /// Configures the counter for free-running up-counting at `prescaler`, wrapping at `0xffff`.
+    /// Used by the RTIC monotonic in [`mono`](crate::mono).
+    unsafe fn start_free_running(&mut self, prescaler: u16);
+    /// Returns the raw counter value.
+    fn counter(&self) -> u16;
+    /// Programs capture/compare channel 1 to fire at `ticks`.
+    unsafe fn set_compare(&mut self, ticks: u16);
+    /// Returns whether the channel-1 compare interrupt flag is set.
+    fn is_compare_pending(&self) -> bool;
+    /// Clears the channel-1 compare interrupt flag.
+    unsafe fn clear_compare_flag(&mut self);
+    /// Enables the channel-1 compare interrupt (`DIER.CC1IE`).
+    unsafe fn enable_compare_interrupt(&mut self);
+    /// Disables the channel-1 compare interrupt (`DIER.CC1IE`).
+    unsafe fn disable_compare_interrupt(&mut self);
+
+    /// Programs `prescaler`/`reload`, runs the counter through one reload period (`reload + 1`
+    /// prescaled ticks), and blocks until the update event fires.
+    unsafe fn delay(&mut self, prescaler: u16, reload: u16) {
+        self.load(prescaler, reload);
+        self.clear_update_flag();
+        self.start();
+        while !self.is_update_pending() {}
+        self.stop();
+    }
+
+    /// Returns the frequency (in Hz) of the clock fed into this timer's prescaler, taking the APB
+    /// prescaler doubling rule into account.
+    #[cfg(feature = "stm32f411")]
+    fn input_clock(clocks: &Clocks) -> u32 {
+        let ppre = Self::APB.ppre(clocks);
+        let pclk_mul = if ppre == 1 { 1 } else { 2 };
+        Self::APB.pclk_hz(clocks) * pclk_mul
+    }
+}
+
+/// Implements [`TimerExt`] for a set of `(peripheral, bus, reset register, enable register, bit)`
+/// tuples, following the same bit-banded enable/reset/down-counting sequence for each.
+macro_rules! timers {
+    ($($TIM:ident: ($apb:expr, $rstr:ident, $enr:ident, $bit:expr),)+) => {
+        $(
+            impl TimerExt for $TIM {
+                const APB: Apb = $apb;
+
+                unsafe fn enable(&mut self) {
+                    // Enable and reset the peripheral.
+                    let rcc = &(*RCC::ptr());
+                    bb::set(&rcc.$enr, $bit);
+                    bb::set(&rcc.$rstr, $bit);
+                    bb::clear(&rcc.$rstr, $bit);
+                    // Select down-counting mode.
+                    self.cr1.modify(|_, w| w.dir().set_bit());
+                }
+
+                unsafe fn disable(&mut self) {
+                    // Disable the peripheral.
+                    let rcc = &(*RCC::ptr());
+                    bb::set(&rcc.$rstr, $bit);
+                    bb::clear(&rcc.$rstr, $bit);
+                    bb::clear(&rcc.$enr, $bit);
+                }
+
+                unsafe fn load(&mut self, prescaler: u16, reload: u16) {
+                    self.psc.write(|w| w.psc().bits(prescaler));
+                    // ARR, not the CNT value written here, is what the counter reloads from on
+                    // every subsequent underflow, so it must be kept in sync with `reload` for
+                    // periodic users (`CountDownTimer`, `UptimeTimer`) to repeat correctly.
+                    self.arr.write(|w| w.arr().bits(reload));
+                    self.cnt.write(|w| unsafe { w.cnt().bits(reload) });
+                }
+
+                unsafe fn start(&mut self) {
+                    self.cr1.modify(|_, w| w.cen().set_bit());
+                }
+
+                unsafe fn stop(&mut self) {
+                    self.cr1.modify(|_, w| w.cen().clear_bit());
+                }
+
+                fn is_update_pending(&self) -> bool {
+                    self.sr.read().uif().bit_is_set()
+                }
+
+                unsafe fn clear_update_flag(&mut self) {
+                    self.sr.write(|w| w.uif().set_bit());
+                }
+
+                unsafe fn enable_update_interrupt(&mut self) {
+                    self.dier.modify(|_, w| w.uie().set_bit());
+                }
+
+                unsafe fn start_free_running(&mut self, prescaler: u16) {
+                    self.psc.write(|w| w.psc().bits(prescaler));
+                    self.arr.write(|w| w.arr().bits(0xffff));
+                    // Up-counting, and only an overflow (not e.g. a CCR write) sets UIF.
+                    self.cr1.modify(|_, w| w.dir().clear_bit().urs().set_bit());
+                    self.cnt.write(|w| unsafe { w.cnt().bits(0) });
+                    // PSC is buffered: force an update event so the new prescaler applies from
+                    // the first tick rather than only after the first overflow.
+                    self.egr.write(|w| w.ug().set_bit());
+                    self.cr1.modify(|_, w| w.cen().set_bit());
+                }
+
+                fn counter(&self) -> u16 {
+                    self.cnt.read().cnt().bits()
+                }
+
+                unsafe fn set_compare(&mut self, ticks: u16) {
+                    self.ccr1.write(|w| unsafe { w.ccr().bits(ticks) });
+                }
+
+                fn is_compare_pending(&self) -> bool {
+                    self.sr.read().cc1if().bit_is_set()
+                }
+
+                unsafe fn clear_compare_flag(&mut self) {
+                    self.sr.write(|w| w.cc1if().set_bit());
+                }
+
+                unsafe fn enable_compare_interrupt(&mut self) {
+                    self.dier.modify(|_, w| w.cc1ie().set_bit());
+                }
+
+                unsafe fn disable_compare_interrupt(&mut self) {
+                    self.dier.modify(|_, w| w.cc1ie().clear_bit());
+                }
+            }
+        )+
+    };
+}
+
+// TIM2 and TIM5 are 32-bit counters on F4 and are intentionally not listed here; see the module
+// doc comment.
+#[cfg(feature = "stm32f411")]
+timers! {
+    TIM1: (Apb::Apb2, apb2rstr, apb2enr, 0),
+    TIM3: (Apb::Apb1, apb1rstr, apb1enr, 1),
+    TIM4: (Apb::Apb1, apb1rstr, apb1enr, 2),
+}
+
+/// Implements [`TimerExt`] for up-counting-only general-purpose timers (no `CR1.DIR` field), such
+/// as TIM9-TIM11 on STM32F4. These count from `0` up to `ARR` and raise `UIF` on overflow, so
+/// `load` programs `ARR` and resets `CNT` to `0` instead of writing `CNT` with the target value.
+macro_rules! up_timers {
+    ($($TIM:ident: ($apb:expr, $rstr:ident, $enr:ident, $bit:expr),)+) => {
+        $(
+            impl TimerExt for $TIM {
+                const APB: Apb = $apb;
+
+                unsafe fn enable(&mut self) {
+                    // Enable and reset the peripheral.
+                    let rcc = &(*RCC::ptr());
+                    bb::set(&rcc.$enr, $bit);
+                    bb::set(&rcc.$rstr, $bit);
+                    bb::clear(&rcc.$rstr, $bit);
+                }
+
+                unsafe fn disable(&mut self) {
+                    // Disable the peripheral.
+                    let rcc = &(*RCC::ptr());
+                    bb::set(&rcc.$rstr, $bit);
+                    bb::clear(&rcc.$rstr, $bit);
+                    bb::clear(&rcc.$enr, $bit);
+                }
+
+                unsafe fn load(&mut self, prescaler: u16, reload: u16) {
+                    self.psc.write(|w| w.psc().bits(prescaler));
+                    self.arr.write(|w| w.arr().bits(reload));
+                    self.cnt.write(|w| unsafe { w.cnt().bits(0) });
+                }
+
+                unsafe fn start(&mut self) {
+                    self.cr1.modify(|_, w| w.cen().set_bit());
+                }
+
+                unsafe fn stop(&mut self) {
+                    self.cr1.modify(|_, w| w.cen().clear_bit());
+                }
+
+                fn is_update_pending(&self) -> bool {
+                    self.sr.read().uif().bit_is_set()
+                }
+
+                unsafe fn clear_update_flag(&mut self) {
+                    self.sr.write(|w| w.uif().set_bit());
+                }
+
+                unsafe fn enable_update_interrupt(&mut self) {
+                    self.dier.modify(|_, w| w.uie().set_bit());
+                }
+
+                unsafe fn start_free_running(&mut self, prescaler: u16) {
+                    self.psc.write(|w| w.psc().bits(prescaler));
+                    self.arr.write(|w| w.arr().bits(0xffff));
+                    // Only an overflow (not e.g. a CCR write) sets UIF.
+                    self.cr1.modify(|_, w| w.urs().set_bit());
+                    self.cnt.write(|w| unsafe { w.cnt().bits(0) });
+                    // PSC is buffered: force an update event so the new prescaler applies from
+                    // the first tick rather than only after the first overflow.
+                    self.egr.write(|w| w.ug().set_bit());
+                    self.cr1.modify(|_, w| w.cen().set_bit());
+                }
+
+                fn counter(&self) -> u16 {
+                    self.cnt.read().cnt().bits()
+                }
+
+                unsafe fn set_compare(&mut self, ticks: u16) {
+                    self.ccr1.write(|w| unsafe { w.ccr().bits(ticks) });
+                }
+
+                fn is_compare_pending(&self) -> bool {
+                    self.sr.read().cc1if().bit_is_set()
+                }
+
+                unsafe fn clear_compare_flag(&mut self) {
+                    self.sr.write(|w| w.cc1if().set_bit());
+                }
+
+                unsafe fn enable_compare_interrupt(&mut self) {
+                    self.dier.modify(|_, w| w.cc1ie().set_bit());
+                }
+
+                unsafe fn disable_compare_interrupt(&mut self) {
+                    self.dier.modify(|_, w| w.cc1ie().clear_bit());
+                }
+            }
+        )+
+    };
+}
+
+#[cfg(feature = "stm32f411")]
+up_timers! {
+    TIM9: (Apb::Apb2, apb2rstr, apb2enr, 16),
+    TIM10: (Apb::Apb2, apb2rstr, apb2enr, 17),
+    TIM11: (Apb::Apb2, apb2rstr, apb2enr, 18),
+}
+
+use embedded_hal::timer::{Cancel, CountDown, Periodic};
+use void::Void;
+
+/// A non-blocking, free-running counter built on top of a [`TimerExt`] peripheral.
+///
+/// Unlike [`TimerDelay`](crate::TimerDelay), `CountDownTimer` does not block: [`start`](CountDown::start)
+/// programs the prescaler and reload and lets the counter run on its own, and
+/// [`wait`](CountDown::wait) merely polls the update flag. This makes it suitable for superloop
+/// polling or as a general-purpose timeout source.
+pub struct CountDownTimer<T> {
+    t: T,
+    freq_in: u32,
+}
+
+impl<T> CountDownTimer<T>
+where
+    T: TimerExt,
+{
+    #[cfg(feature = "stm32f411")]
+    pub fn init(mut t: T, clocks: &Clocks) -> CountDownTimer<T> {
+        unsafe {
+            t.enable();
+        };
+        let freq_in = T::input_clock(clocks);
+        CountDownTimer { t, freq_in }
+    }
+
+    pub fn free(mut self) -> T {
+        unsafe {
+            self.t.disable();
+        }
+        self.t
+    }
+}
+
+/// Picks the smallest prescaler that brings `total_ticks` native timer ticks within range of the
+/// 16-bit reload register, and the corresponding reload value.
+///
+/// `total_ticks` must fit in `0xffff * 0x10000` ticks (prescaler and reload both maxed out); this
+/// is the actual ceiling regardless of what `CountDown::Time` advertises. Debug builds assert
+/// against exceeding it rather than silently programming a shorter timeout than requested.
+pub(crate) fn pre_reload(total_ticks: u64) -> (u16, u16) {
+    debug_assert!(
+        total_ticks <= 0xffff * 0x1_0000,
+        "requested delay does not fit in a single prescaler/reload pair"
+    );
+    let prescaler = ((total_ticks / 0x1_0000) as u32).min(0xffff) as u16;
+    let reload = (total_ticks / (prescaler as u64 + 1)).min(0xffff) as u16;
+    (prescaler, reload)
+}
+
+impl<T> CountDown for CountDownTimer<T>
+where
+    T: TimerExt,
+{
+    /// Microseconds. Note the real ceiling is set by [`pre_reload`]'s prescaler/reload range, not
+    /// by this type: at this timer's input clock, that's `0xffff * 0x10000` ticks, which can be
+    /// well short of the ~71 minutes a `u32` microsecond count could otherwise express.
+    type Time = u32;
+
+    fn start<D>(&mut self, count: D)
+    where
+        D: Into<Self::Time>,
+    {
+        let total_ticks = (count.into() as u64) * (self.freq_in as u64) / 1_000_000;
+        let (prescaler, reload) = pre_reload(total_ticks);
+        unsafe {
+            self.t.load(prescaler, reload);
+            self.t.clear_update_flag();
+            self.t.start();
+        }
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        if self.t.is_update_pending() {
+            unsafe {
+                self.t.clear_update_flag();
+            }
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl<T> Periodic for CountDownTimer<T> where T: TimerExt {}
+
+impl<T> Cancel for CountDownTimer<T>
+where
+    T: TimerExt,
+{
+    type Error = core::convert::Infallible;
+
+    fn cancel(&mut self) -> Result<(), Self::Error> {
+        unsafe {
+            self.t.stop();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+//! Interrupt-driven millisecond uptime counter built on a [`TimerExt`] peripheral.
+//!
+//! This is a cheap system-uptime / software-timeout primitive for boards where `SysTick` is
+//! already claimed (RTIC reserves it for its own scheduling, for example). Configure an
+//! [`UptimeTimer`] for periodic 1ms update interrupts, unmask its NVIC interrupt, and call
+//! [`default_ms_irq_handler`] from the corresponding `#[interrupt]` handler; [`millis`] then
+//! returns the number of milliseconds elapsed since [`UptimeTimer::init`].
+
+use core::cell::Cell;
+use cortex_m::interrupt::Mutex;
+#[cfg(feature = "stm32f411")]
+use stm32f4xx_hal::rcc::Clocks;
+
+use crate::timer::{pre_reload, TimerExt};
+
+static MS_COUNTER: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// Configures a timer peripheral for periodic 1ms update-event interrupts.
+///
+/// The caller is still responsible for unmasking the peripheral's interrupt in the NVIC; this
+/// type only programs the timer itself.
+pub struct UptimeTimer<T> {
+    t: T,
+}
+
+impl<T> UptimeTimer<T>
+where
+    T: TimerExt,
+{
+    #[cfg(feature = "stm32f411")]
+    pub fn init(mut t: T, clocks: &Clocks) -> UptimeTimer<T> {
+        unsafe {
+            t.enable();
+        }
+        let freq_in = T::input_clock(clocks);
+        let total_ticks = (freq_in as u64) / 1000;
+        let (prescaler, reload) = pre_reload(total_ticks);
+        // `load` programs both PSC and ARR, so every underflow after the first reuses this same
+        // 1ms period rather than drifting to the hardware-reset ARR value.
+        unsafe {
+            t.load(prescaler, reload);
+            t.clear_update_flag();
+            t.enable_update_interrupt();
+            t.start();
+        }
+        UptimeTimer { t }
+    }
+
+    pub fn free(mut self) -> T {
+        unsafe {
+            self.t.disable();
+        }
+        self.t
+    }
+}
+
+/// Advances the global millisecond counter by one and clears `t`'s update event flag.
+///
+/// Call this from the `#[interrupt]` handler of whichever timer peripheral was configured with
+/// [`UptimeTimer::init`].
+pub fn default_ms_irq_handler<T>(t: &mut T)
+where
+    T: TimerExt,
+{
+    unsafe {
+        t.clear_update_flag();
+    }
+    cortex_m::interrupt::free(|cs| {
+        let counter = MS_COUNTER.borrow(cs);
+        counter.set(counter.get().wrapping_add(1));
+    });
+}
+
+/// Returns the number of milliseconds elapsed since the uptime timer was started.
+pub fn millis() -> u64 {
+    cortex_m::interrupt::free(|cs| MS_COUNTER.borrow(cs).get())
+}
+
+/// Returns the number of milliseconds elapsed since `since`, as previously returned by
+/// [`millis`].
+pub fn elapsed(since: u64) -> u64 {
+    millis().wrapping_sub(since)
+}